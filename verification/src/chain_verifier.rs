@@ -1,5 +1,8 @@
 //! Bitcoin chain verifier
 
+extern crate rayon;
+
+use std::sync::Arc;
 use hash::H256;
 use chain::{IndexedBlock, IndexedBlockHeader, BlockHeader, Transaction};
 use db::{SharedStore, TransactionOutputProvider, BlockHeaderProvider, BlockOrigin};
@@ -16,10 +19,60 @@ use deployments::Deployments;
 use ConsensusLimitsRef;
 use Verify;
 
+/// Controls how thoroughly a block is verified.
+///
+/// `Header` and `NoVerification` trade safety for sync speed and are only
+/// ever applied up to a known-good `verification_edge` - see
+/// `BackwardsCompatibleChainVerifier::with_verification_edge`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationLevel {
+	/// Run every check, including per-input script verification.
+	Full,
+	/// Run header checks and the cheap `ChainVerifier` preverification, but
+	/// skip per-input script evaluation in `ChainAcceptor`.
+	Header,
+	/// Run only structural/header checks; `ChainAcceptor` is not invoked at all.
+	NoVerification,
+}
+
+/// Median time of `tip` and up to 10 of its ancestors, as used by BIP113 for
+/// finality and by BIP68 for relative-locktime comparisons. Falls back to
+/// the current system time if `header_provider` has no header for `tip`.
+fn median_time_past(header_provider: &BlockHeaderProvider, tip: &H256) -> u32 {
+	let mut timestamps: Vec<u32> = Vec::with_capacity(11);
+	let mut hash = tip.clone();
+	while timestamps.len() < 11 {
+		match header_provider.block_header(hash.clone().into()) {
+			Some(header) => {
+				timestamps.push(header.time);
+				hash = header.previous_header_hash;
+			},
+			None => break,
+		}
+	}
+
+	if timestamps.is_empty() {
+		return ::time::get_time().sec as u32;
+	}
+
+	timestamps.sort();
+	timestamps[timestamps.len() / 2]
+}
+
 pub struct BackwardsCompatibleChainVerifier {
 	store: SharedStore,
 	network: Magic,
 	deployments: Deployments,
+	/// Verification level applied to blocks at or below `verification_edge`.
+	verification_level: VerificationLevel,
+	/// Once a block with this hash has been accepted, verification reverts
+	/// to `Full` for every following block. Defaults to the zero hash, which
+	/// matches no real block; fast sync is still off by default because
+	/// `verification_level` defaults to `Full`, until `with_verification_edge`
+	/// configures both fields together.
+	verification_edge: H256,
+	/// Thread pool used to parallelize per-transaction script verification.
+	thread_pool: Arc<rayon::ThreadPool>,
 }
 
 impl BackwardsCompatibleChainVerifier {
@@ -28,41 +81,99 @@ impl BackwardsCompatibleChainVerifier {
 			store: store,
 			network: network,
 			deployments: Deployments::new(),
+			verification_level: VerificationLevel::Full,
+			verification_edge: H256::default(),
+			thread_pool: Arc::new(rayon::ThreadPool::new(rayon::Configuration::new()).expect("rayon::ThreadPool::new with default configuration never fails")),
+		}
+	}
+
+	/// Enables fast initial sync: blocks at or below `verification_edge` are
+	/// verified at `verification_level` instead of `Full`. Once the edge
+	/// block itself has been accepted, later blocks are always `Full`.
+	pub fn with_verification_edge(mut self, verification_level: VerificationLevel, verification_edge: H256) -> Self {
+		self.verification_level = verification_level;
+		self.verification_edge = verification_edge;
+		self
+	}
+
+	/// Bounds the number of threads used for parallel per-transaction
+	/// script verification. Defaults to rayon's own default (one thread
+	/// per CPU core).
+	pub fn with_thread_count(mut self, thread_count: usize) -> Self {
+		let configuration = rayon::Configuration::new().num_threads(thread_count);
+		self.thread_pool = Arc::new(rayon::ThreadPool::new(configuration).expect("rayon::ThreadPool::new with a valid thread count never fails"));
+		self
+	}
+
+	/// Returns the verification level to apply to a block at `block_number`.
+	fn verification_level(&self, block_number: u32) -> VerificationLevel {
+		if self.verification_level == VerificationLevel::Full {
+			return VerificationLevel::Full;
+		}
+
+		match self.store.block_number(&self.verification_edge) {
+			// the edge has already been accepted - verify everything after it fully
+			Some(edge_number) if block_number > edge_number => VerificationLevel::Full,
+			// edge not yet known, or this block is still at/before it
+			_ => self.verification_level,
 		}
 	}
 
 	fn verify_block(&self, block: &IndexedBlock, limits: &ConsensusLimitsRef) -> Result<(), Error> {
 		let current_time = ::time::get_time().sec as u32;
-		// first run pre-verification
-		let chain_verifier = ChainVerifier::new(block, self.network, current_time, limits);
-		chain_verifier.check()?;
 
 		assert_eq!(Some(self.store.best_block().hash), self.store.block_hash(self.store.best_block().number));
 		let block_origin = self.store.block_origin(&block.header)?;
 		trace!(target: "verification", "verify_block: {:?} best_block: {:?} block_origin: {:?}", block.hash().reversed(), self.store.best_block(), block_origin);
+
+		let block_number = match block_origin {
+			BlockOrigin::KnownBlock => unreachable!(),
+			BlockOrigin::CanonChain { block_number } => block_number,
+			BlockOrigin::SideChain(ref origin) => origin.block_number,
+			BlockOrigin::SideChainBecomingCanonChain(ref origin) => origin.block_number,
+		};
+
+		// first run pre-verification
+		let chain_verifier = ChainVerifier::new(block, self.network, current_time, block_number, limits);
+		chain_verifier.check()?;
+
+		let level = self.verification_level(block_number);
+		if level == VerificationLevel::NoVerification {
+			// trust the structural/header checks we've already run and skip
+			// ChainAcceptor entirely
+			return Ok(());
+		}
+
+		// BIP113: median-time-past, not current_time, decides finality and BIP68 relative locktime
+		let header_provider = self.store.as_block_header_provider();
+
 		match block_origin {
 			BlockOrigin::KnownBlock => {
 				// there should be no known blocks at this point
 				unreachable!();
 			},
 			BlockOrigin::CanonChain { block_number } => {
+				let median_time_past = median_time_past(header_provider, &block.header.raw.previous_header_hash);
 				let canon_block = CanonBlock::new(block);
-				let chain_acceptor = ChainAcceptor::new(self.store.as_store(), self.network, canon_block, block_number, &self.deployments, limits);
-				chain_acceptor.check()?;
+				let chain_acceptor = ChainAcceptor::new(self.store.as_store(), self.network, canon_block, block_number, &self.deployments, limits, level, median_time_past, &self.thread_pool);
+				// keep script verification on our bounded pool, not rayon's global one
+				self.thread_pool.install(|| chain_acceptor.check())?;
 			},
 			BlockOrigin::SideChain(origin) => {
 				let block_number = origin.block_number;
+				let median_time_past = median_time_past(header_provider, &block.header.raw.previous_header_hash);
 				let fork = self.store.fork(origin)?;
 				let canon_block = CanonBlock::new(block);
-				let chain_acceptor = ChainAcceptor::new(fork.store(), self.network, canon_block, block_number, &self.deployments, limits);
-				chain_acceptor.check()?;
+				let chain_acceptor = ChainAcceptor::new(fork.store(), self.network, canon_block, block_number, &self.deployments, limits, level, median_time_past, &self.thread_pool);
+				self.thread_pool.install(|| chain_acceptor.check())?;
 			},
 			BlockOrigin::SideChainBecomingCanonChain(origin) => {
 				let block_number = origin.block_number;
+				let median_time_past = median_time_past(header_provider, &block.header.raw.previous_header_hash);
 				let fork = self.store.fork(origin)?;
 				let canon_block = CanonBlock::new(block);
-				let chain_acceptor = ChainAcceptor::new(fork.store(), self.network, canon_block, block_number, &self.deployments, limits);
-				chain_acceptor.check()?;
+				let chain_acceptor = ChainAcceptor::new(fork.store(), self.network, canon_block, block_number, &self.deployments, limits, level, median_time_past, &self.thread_pool);
+				self.thread_pool.install(|| chain_acceptor.check())?;
 			},
 		}
 
@@ -91,16 +202,20 @@ impl BackwardsCompatibleChainVerifier {
 		time: u32,
 		transaction: &Transaction,
         limits: &ConsensusLimitsRef,
-	) -> Result<(), TransactionError> where T: TransactionOutputProvider {
+	) -> Result<(), TransactionError> where T: TransactionOutputProvider + Sync {
 		let indexed_tx = transaction.clone().into();
 		// let's do preverification first
-		let tx_verifier = MemoryPoolTransactionVerifier::new(&indexed_tx, limits);
+		let tx_verifier = MemoryPoolTransactionVerifier::new(&indexed_tx, height, limits);
 		try!(tx_verifier.check());
 
 		let canon_tx = CanonTransaction::new(&indexed_tx);
 		// now let's do full verification
 		let noop = NoopStore;
 		let output_store = DuplexTransactionOutputProvider::new(prevout_provider, &noop);
+		// BIP113: median-time-past, not current_time, decides finality and BIP68 relative locktime
+		let median_time_past = median_time_past(self.store.as_block_header_provider(), &self.store.best_block().hash);
+		// UAHF sighash fork id, if active at this height
+		let sighash_fork_id = limits.sighash_fork_id(height);
 		let tx_acceptor = MemoryPoolTransactionAcceptor::new(
 			self.store.as_transaction_meta_provider(),
 			output_store,
@@ -108,9 +223,11 @@ impl BackwardsCompatibleChainVerifier {
 			canon_tx,
 			height,
 			time,
+			median_time_past,
 			&self.deployments,
 			self.store.as_block_header_provider(),
-            limits.max_block_sigops(),
+            limits.max_block_sigops(height),
+			sighash_fork_id,
 		);
 		tx_acceptor.check()
 	}
@@ -3,9 +3,13 @@ const LEGACY_MAX_BLOCK_SIZE: usize = 1_000_000;
 const LEGACY_MAX_BLOCK_SIGOPS: usize = 20_000;
 
 pub trait ConsensusLimits : Send + Sync {
-	fn max_block_sigops(&self) -> usize;
-	fn max_block_size(&self) -> usize;
-    fn max_transaction_size(&self) -> usize;
+	fn max_block_sigops(&self, height: u32) -> usize;
+	fn max_block_size(&self, height: u32) -> usize;
+    fn max_transaction_size(&self, height: u32) -> usize;
+    /// The UAHF replay-protected sighash fork id to require at `height`, if
+    /// the active consensus fork (if any) mandates one. `None` means
+    /// transactions must use the legacy sighash.
+    fn sighash_fork_id(&self, _height: u32) -> Option<u8> { None }
 }
 
 // Temporary limits to mitigate DOS in Bitcoins infancy.
@@ -18,9 +22,104 @@ impl LegacyLimits {
 }
 
 impl ConsensusLimits for LegacyLimits {
-    fn max_block_sigops(&self) -> usize { LEGACY_MAX_BLOCK_SIGOPS }
-	fn max_block_size(&self) -> usize { LEGACY_MAX_BLOCK_SIZE }
-    fn max_transaction_size(&self) -> usize { LEGACY_MAX_BLOCK_SIZE }
+    fn max_block_sigops(&self, _height: u32) -> usize { LEGACY_MAX_BLOCK_SIGOPS }
+	fn max_block_size(&self, _height: u32) -> usize { LEGACY_MAX_BLOCK_SIZE }
+    fn max_transaction_size(&self, _height: u32) -> usize { LEGACY_MAX_BLOCK_SIZE }
+}
+
+/// Describes a consensus fork that raises the block size limit at a given
+/// activation height, e.g. the Bitcoin Cash / SegWit2x block size increase.
+pub struct ConsensusFork {
+    /// Height at which the fork's limits take effect.
+    pub activation_height: u32,
+    /// Block size limit once the fork is active.
+    pub max_block_size: usize,
+    /// Transaction size limit once the fork is active. Kept distinct from
+    /// `max_block_size`: a fork that scales the block size up doesn't
+    /// necessarily want to let a single transaction fill it.
+    pub max_transaction_size: usize,
+    /// UAHF replay-protected sighash fork id, mixed into the sighash type
+    /// byte once the fork is active. `None` means the fork doesn't require
+    /// replay protection (e.g. a plain SegWit2x size bump).
+    pub sighash_fork_id: Option<u8>,
+}
+
+impl ConsensusFork {
+    /// Bitcoin Cash-style fork: raises the block size to 8 MB at
+    /// `activation_height`, keeps the legacy 1 MB transaction size cap, and
+    /// requires the UAHF replay-protected sighash (fork id 0) from that
+    /// height onwards.
+    pub fn bitcoin_cash(activation_height: u32) -> Self {
+        const BITCOIN_CASH_MAX_BLOCK_SIZE: usize = 8_000_000;
+        const BITCOIN_CASH_SIGHASH_FORK_ID: u8 = 0x00;
+        ConsensusFork {
+            activation_height: activation_height,
+            max_block_size: BITCOIN_CASH_MAX_BLOCK_SIZE,
+            max_transaction_size: LEGACY_MAX_BLOCK_SIZE,
+            sighash_fork_id: Some(BITCOIN_CASH_SIGHASH_FORK_ID),
+        }
+    }
+
+    /// Whether `height` is at or after the fork's activation height.
+    pub fn is_active(&self, height: u32) -> bool {
+        height >= self.activation_height
+    }
+
+    /// The UAHF replay-protected sighash fork id to require at `height`, if any.
+    pub fn sighash_fork_id(&self, height: u32) -> Option<u8> {
+        if self.is_active(height) {
+            self.sighash_fork_id
+        } else {
+            None
+        }
+    }
+}
+
+/// Consensus limits that behave like `LegacyLimits` before `fork`'s
+/// activation height, and switch to `fork`'s enlarged block size after it.
+/// The sigops budget always scales with the active block size, at a rate of
+/// one sigop per 50 bytes - which happens to reproduce the legacy 20k sigops
+/// limit pre-fork (1,000,000 / 50 == 20,000).
+pub struct ForkLimits {
+    fork: ConsensusFork,
+}
+
+impl ForkLimits {
+    pub fn new(fork: ConsensusFork) -> Self {
+        ForkLimits { fork: fork }
+    }
+
+    /// The consensus fork backing these limits, for callers holding a
+    /// concrete `ForkLimits` rather than the `ConsensusLimits` trait object.
+    pub fn fork(&self) -> &ConsensusFork {
+        &self.fork
+    }
+}
+
+impl ConsensusLimits for ForkLimits {
+    fn max_block_sigops(&self, height: u32) -> usize {
+        self.max_block_size(height) / 50
+    }
+
+    fn max_block_size(&self, height: u32) -> usize {
+        if self.fork.is_active(height) {
+            self.fork.max_block_size
+        } else {
+            LEGACY_MAX_BLOCK_SIZE
+        }
+    }
+
+    fn max_transaction_size(&self, height: u32) -> usize {
+        if self.fork.is_active(height) {
+            self.fork.max_transaction_size
+        } else {
+            LEGACY_MAX_BLOCK_SIZE
+        }
+    }
+
+    fn sighash_fork_id(&self, height: u32) -> Option<u8> {
+        self.fork.sighash_fork_id(height)
+    }
 }
 
 pub type ConsensusLimitsRef = Arc<ConsensusLimits>;